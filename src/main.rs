@@ -2,9 +2,20 @@
 //!
 //! Footnotes are included like this: `Normal text{{footnote: Or is it?}} in body.`
 //!
-//! The `markdown` boolean config value indicates that MarkDown should be emitted for
-//! the generated footnotes, rather than HTML.
+//! The `output` config value selects the format used for the generated footnotes:
+//! `"html"`, `"markdown"`, or `"auto"` (the default) to derive it from the active
+//! renderer.  To restrict this preprocessor to specific renderers, use mdbook's own
+//! `preprocessor.footnote.renderers` config key; mdbook checks it before ever invoking
+//! the preprocessor, so there's nothing for this binary to do itself.
+//! The older `markdown` boolean is still honored as a deprecated alias for `output`.
+//!
+//! Footnotes are the built-in default of a more general span-rewriting engine: the
+//! `preprocessor.footnote.markers` array in `book.toml` can declare further `{{name: ..}}`-style
+//! markers, each with a regex `pattern` capturing a `content` group and an `html`/`markdown`
+//! replacement template. A `mark` marker (`{{mark: text}}` -> `<mark>text</mark>`) is registered
+//! by default and can be overridden by declaring another marker with the same name.
 use clap::{App, Arg, SubCommand};
+use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use log::warn;
 use mdbook::{
@@ -12,8 +23,10 @@ use mdbook::{
     errors::Error,
     preprocess::{CmdPreprocessor, Preprocessor, PreprocessorContext},
 };
+use pulldown_cmark::{Event, Parser, Tag};
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 use std::{io, process};
 
 /// Name of this preprocessor.
@@ -36,14 +49,14 @@ fn main() {
         let renderer = sub_args.value_of("renderer").expect("Required argument");
 
         // Signal whether the renderer is supported by exiting with 1 or 0.
-        if Footnote::supports_renderer(&renderer) {
+        if Footnote::supports_renderer(renderer) {
             process::exit(0);
         } else {
             process::exit(1);
         }
     } else {
         let (ctx, book) = CmdPreprocessor::parse_input(io::stdin()).expect("Failed to parse input");
-        let preprocessor = Footnote::new(&ctx);
+        let preprocessor = Footnote::new(&ctx).expect("Failed to initialize preprocessor");
 
         let processed_book = preprocessor
             .run(&ctx, book)
@@ -53,8 +66,14 @@ fn main() {
 }
 
 lazy_static! {
-    static ref FOOTNOTE_RE: Regex =
-        Regex::new(r"(?s)\{\{footnote:\s*(?P<content>.*?)\}\}").unwrap();
+    /// Matches `{{footnote:name= ..}}` (named definition) or a bare `{{footnote: ..}}`/
+    /// `{{footnote:word}}`. The latter is disambiguated at substitution time: it's treated as a
+    /// reference to `word` only if that label was already defined, and as an ordinary anonymous
+    /// footnote (whose content is the captured text) otherwise.
+    static ref FOOTNOTE_RE: Regex = Regex::new(
+        r"(?s)\{\{footnote:(?:(?P<label>[A-Za-z0-9_-]+)=\s*(?P<content>.*?)|\s*(?P<bare>.*?))\}\}"
+    )
+    .unwrap();
 
     /// Names of known renderers which deal in HTML output.
     static ref HTML_RENDERERS: HashSet<String> = {
@@ -65,17 +84,111 @@ lazy_static! {
     };
 }
 
-/// A pre-processor that expands {{footnote: ..}} markers.
-#[derive(Default)]
+/// Byte ranges within `content` that should be left untouched by marker substitution — fenced or
+/// indented code blocks and inline code spans — derived from a single `pulldown_cmark` parse of
+/// the chapter. Markers are then matched against the raw source string itself (so a marker's
+/// content can freely contain nested markdown without tripping up the match), with any match
+/// overlapping one of these ranges skipped.
+fn excluded_ranges(content: &str) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut code_block_start = None;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => code_block_start = Some(range.start),
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some(start) = code_block_start.take() {
+                    ranges.push(start..range.end);
+                }
+            }
+            Event::Code(_) => ranges.push(range),
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// Whether `range` overlaps any of the `excluded` ranges.
+fn overlaps_excluded(range: &Range<usize>, excluded: &[Range<usize>]) -> bool {
+    excluded
+        .iter()
+        .any(|e| range.start < e.end && e.start < range.end)
+}
+
+/// How generated footnote markup should be emitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputMode {
+    Html,
+    Markdown,
+}
+
+/// What a `MarkerRule` match is turned into.
+enum MarkerKind {
+    /// The built-in numbered-footnote behavior implemented by `Footnote::footnote_replacement`.
+    Footnote,
+    /// A plain "replace the match with this template, `{}` standing in for the captured
+    /// `content` group" transform, e.g. the built-in `mark` highlight marker.
+    Simple {
+        html_template: String,
+        markdown_template: String,
+    },
+}
+
+/// A single configured `{{name: ..}}`-style span transform, tried against every chapter in
+/// registration order. `pattern` must capture a `content` group (ignored by `MarkerKind::Footnote`,
+/// which defines its own capture groups).
+struct MarkerRule {
+    name: String,
+    pattern: Regex,
+    kind: MarkerKind,
+}
+
+/// The built-in default marker: numbered footnotes, matched by `FOOTNOTE_RE`.
+fn default_footnote_rule() -> MarkerRule {
+    MarkerRule {
+        name: "footnote".to_owned(),
+        pattern: FOOTNOTE_RE.clone(),
+        kind: MarkerKind::Footnote,
+    }
+}
+
+/// The built-in `{{mark: text}}` -> `<mark>text</mark>` highlight marker.
+fn default_mark_rule() -> MarkerRule {
+    MarkerRule {
+        name: "mark".to_owned(),
+        pattern: Regex::new(r"(?s)\{\{mark:\s*(?P<content>.*?)\}\}").unwrap(),
+        kind: MarkerKind::Simple {
+            html_template: "<mark>{}</mark>".to_owned(),
+            markdown_template: "<mark>{}</mark>".to_owned(),
+        },
+    }
+}
+
+/// A pre-processor that expands `{{name: ..}}`-style span markers in every chapter, in a single
+/// pass over each chapter's source. Numbered footnotes are the built-in default marker; further
+/// markers can be configured under `preprocessor.footnote.markers`.
 pub struct Footnote {
-    md_footnotes: bool,
+    output: OutputMode,
+    /// Registered span markers, in the order they're applied. Always starts with the built-in
+    /// `footnote` marker.
+    markers: Vec<MarkerRule>,
 }
 
 impl Footnote {
-    fn new(ctx: &PreprocessorContext) -> Self {
-        if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
-            // We should probably use the `semver` crate to check compatibility
-            // here...
+    fn new(ctx: &PreprocessorContext) -> Result<Self, Error> {
+        let book_version = semver::Version::parse(&ctx.mdbook_version).map_err(|e| {
+            Error::msg(format!(
+                "Failed to parse mdbook version '{}': {}",
+                ctx.mdbook_version, e
+            ))
+        })?;
+        let version_req = semver::VersionReq::parse(mdbook::MDBOOK_VERSION).map_err(|e| {
+            Error::msg(format!(
+                "Failed to parse required mdbook version '{}': {}",
+                mdbook::MDBOOK_VERSION,
+                e
+            ))
+        })?;
+        if !version_req.matches(&book_version) {
             warn!(
                 "The {} plugin was built against version {} of mdbook, \
              but we're being called from version {}",
@@ -84,22 +197,109 @@ impl Footnote {
                 ctx.mdbook_version
             );
         }
-        let md_footnotes = if let Some(toml::Value::Boolean(markdown)) =
-            ctx.config.get("preprocessor.footnote.markdown")
-        {
-            *markdown
-        } else {
-            false
+        let auto_output = || {
+            if HTML_RENDERERS.contains(&ctx.renderer) {
+                OutputMode::Html
+            } else {
+                OutputMode::Markdown
+            }
+        };
+        let output = match ctx.config.get("preprocessor.footnote.output") {
+            Some(toml::Value::String(mode)) => match mode.as_str() {
+                "html" => OutputMode::Html,
+                "markdown" => OutputMode::Markdown,
+                "auto" => auto_output(),
+                other => {
+                    warn!(
+                        "Unrecognized preprocessor.footnote.output value '{}', falling back to 'auto'",
+                        other
+                    );
+                    auto_output()
+                }
+            },
+            _ => {
+                // Deprecated alias: `markdown = true/false` used to be the only way to pick a
+                // format.  Keep honoring it when the new `output` key isn't present.
+                match ctx.config.get("preprocessor.footnote.markdown") {
+                    Some(toml::Value::Boolean(markdown)) => {
+                        warn!(
+                            "preprocessor.footnote.markdown is deprecated, use \
+                             preprocessor.footnote.output = \"markdown\"|\"html\"|\"auto\" instead"
+                        );
+                        if *markdown {
+                            OutputMode::Markdown
+                        } else {
+                            OutputMode::Html
+                        }
+                    }
+                    _ => auto_output(),
+                }
+            }
         };
 
-        if !md_footnotes && !HTML_RENDERERS.contains(&ctx.renderer) {
+        if output == OutputMode::Html && !HTML_RENDERERS.contains(&ctx.renderer) {
             warn!(
                 "Emitting HTML footnotes for renderer '{}' which may not be HTML-based",
                 ctx.renderer,
             );
         }
 
-        Self { md_footnotes }
+        // `footnote` is always the first marker applied; it can't be reconfigured away since its
+        // replacement logic (numbering, dedup) isn't expressible as a simple template.
+        let mut markers = vec![default_footnote_rule(), default_mark_rule()];
+        if let Some(toml::Value::Array(entries)) = ctx.config.get("preprocessor.footnote.markers") {
+            for entry in entries {
+                let table = match entry {
+                    toml::Value::Table(table) => table,
+                    _ => {
+                        warn!("Ignoring non-table entry in preprocessor.footnote.markers");
+                        continue;
+                    }
+                };
+                let name = table.get("name").and_then(toml::Value::as_str);
+                let pattern = table.get("pattern").and_then(toml::Value::as_str);
+                let html = table.get("html").and_then(toml::Value::as_str);
+                let (name, pattern, html) = match (name, pattern, html) {
+                    (Some(name), Some(pattern), Some(html)) => (name, pattern, html),
+                    _ => {
+                        warn!("Ignoring preprocessor.footnote.markers entry missing 'name', 'pattern' or 'html'");
+                        continue;
+                    }
+                };
+                if name == "footnote" {
+                    warn!("The 'footnote' marker name is reserved for the built-in footnote marker; ignoring");
+                    continue;
+                }
+                let regex = match Regex::new(pattern) {
+                    Ok(regex) => regex,
+                    Err(e) => {
+                        warn!("Ignoring marker '{}' with invalid pattern: {}", name, e);
+                        continue;
+                    }
+                };
+                let markdown = table
+                    .get("markdown")
+                    .and_then(toml::Value::as_str)
+                    .unwrap_or(html)
+                    .to_owned();
+                // A user-declared marker with the same name as a built-in (e.g. "mark")
+                // replaces it, so book.toml can customize the defaults.
+                markers.retain(|m| m.name != name);
+                markers.push(MarkerRule {
+                    name: name.to_owned(),
+                    pattern: regex,
+                    kind: MarkerKind::Simple {
+                        html_template: html.to_owned(),
+                        markdown_template: markdown,
+                    },
+                });
+            }
+        }
+
+        Ok(Self {
+            output,
+            markers,
+        })
     }
 
     /// Indicate whether a renderer is supported.  This preprocessor can emit MarkDown so should support almost any
@@ -107,6 +307,181 @@ impl Footnote {
     fn supports_renderer(renderer: &str) -> bool {
         renderer != "not-supported"
     }
+
+    /// Compute the replacement text for one footnote match, updating the running `footnotes`
+    /// (label -> content, insertion order == display order) and `occurrences` (label -> number
+    /// of body references seen so far) maps as it goes.
+    fn footnote_replacement(
+        &self,
+        caps: &regex::Captures<'_>,
+        footnotes: &mut IndexMap<String, String>,
+        occurrences: &mut HashMap<String, usize>,
+        anon_count: &mut usize,
+    ) -> String {
+        let label = if let Some(label) = caps.name("label") {
+            let label = label.as_str().to_owned();
+            let note_content = caps.name("content").unwrap().as_str().to_owned();
+            if footnotes.contains_key(&label) {
+                warn!("Footnote '{}' redefined; keeping original definition", label);
+            } else {
+                footnotes.insert(label.clone(), note_content);
+            }
+            label
+        } else {
+            // A bare `{{footnote:word}}` is a reference only if `word` is already a known
+            // label; otherwise it's an ordinary anonymous footnote whose content happens to
+            // be that one word, matching the original `{{footnote: ..}}` syntax. This avoids
+            // misreading a plain single-word anonymous note (e.g. `{{footnote:TODO}}`) as a
+            // reference, and avoids it silently latching onto an unrelated same-named
+            // footnote defined elsewhere in the book.
+            let bare = caps.name("bare").unwrap().as_str();
+            let trimmed = bare.trim();
+            let is_identifier = !trimmed.is_empty()
+                && trimmed.len() == bare.len()
+                && trimmed
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if is_identifier && footnotes.contains_key(trimmed) {
+                trimmed.to_owned()
+            } else {
+                *anon_count += 1;
+                let label = format!("\0anon-{}", anon_count);
+                footnotes.insert(label.clone(), bare.to_owned());
+                label
+            }
+        };
+
+        let idx = footnotes.get_index_of(&label).unwrap() + 1;
+        let occurrence = {
+            let count = occurrences.entry(label).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        if self.output == OutputMode::Markdown {
+            format!("[^{}]", idx)
+        } else {
+            format!(
+                "<sup><a name=\"to-footnote-{}-{}\">[{}](#footnote-{})</a></sup>",
+                idx, occurrence, idx, idx
+            )
+        }
+    }
+
+    /// Render the trailing footnote-definitions block for chapters that used any footnotes.
+    fn footnote_trailer(
+        &self,
+        footnotes: IndexMap<String, String>,
+        occurrences: &HashMap<String, usize>,
+    ) -> String {
+        let mut trailer = String::new();
+        if self.output == OutputMode::Markdown {
+            trailer.push_str("<p><hr/>\n");
+        } else {
+            trailer.push_str("\n---\n");
+        }
+        for (idx, (label, note_content)) in footnotes.into_iter().enumerate() {
+            let idx = idx + 1;
+            if self.output == OutputMode::Markdown {
+                trailer.push_str(&format!("\n\n[^{}]: {}", idx, note_content));
+            } else {
+                let refs = occurrences.get(&label).copied().unwrap_or(1);
+                let back_links = (1..=refs)
+                    .map(|m| format!("[{}](#to-footnote-{}-{})", m, idx, m))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                trailer.push_str(&format!(
+                    "\n\n<a name=\"footnote-{}\">{}</a>: {}",
+                    idx, back_links, note_content
+                ));
+            }
+        }
+        trailer
+    }
+
+    /// Expand every registered marker (see `MarkerRule`) in a chapter's content in a single pass,
+    /// leaving any match that overlaps a code block or inline code span untouched.
+    ///
+    /// Matching happens directly against the chapter's raw markdown source (so a marker's
+    /// content can freely contain nested markdown without splitting the match across several
+    /// `pulldown_cmark` events); a single parse of that source is used only to compute the byte
+    /// ranges of code regions to exclude. See `excluded_ranges`.
+    fn expand_markers(&self, chap: &mut mdbook::book::Chapter) -> Result<(), Error> {
+        let content = chap.content.clone();
+        let excluded = excluded_ranges(&content);
+
+        // Ordered map from footnote label to its content, so the same label referenced several
+        // times shares one definition and one assigned index (insertion order == display order).
+        // Anonymous footnotes get a synthesized label that an author could never type.
+        let mut footnotes: IndexMap<String, String> = IndexMap::new();
+        let mut occurrences: HashMap<String, usize> = HashMap::new();
+        let mut anon_count = 0usize;
+
+        // Every match from every rule, gathered up front so the whole chapter is rewritten in
+        // one linear scan instead of one re-scan per marker.
+        let mut matches: Vec<(Range<usize>, usize, String)> = vec![];
+        for (rule_index, rule) in self.markers.iter().enumerate() {
+            for caps in rule.pattern.captures_iter(&content) {
+                let whole = caps.get(0).unwrap();
+                if overlaps_excluded(&whole.range(), &excluded) {
+                    continue;
+                }
+                let text = match &rule.kind {
+                    MarkerKind::Footnote => {
+                        self.footnote_replacement(&caps, &mut footnotes, &mut occurrences, &mut anon_count)
+                    }
+                    MarkerKind::Simple {
+                        html_template,
+                        markdown_template,
+                    } => {
+                        let note_content = caps.name("content").map_or("", |m| m.as_str());
+                        let template = if self.output == OutputMode::Markdown {
+                            markdown_template
+                        } else {
+                            html_template
+                        };
+                        template.replacen("{}", note_content, 1)
+                    }
+                };
+                matches.push((whole.range(), rule_index, text));
+            }
+        }
+        // `matches` is in registration order, so each match is checked only against matches
+        // already kept from the same or earlier-registered rules.
+        let mut kept: Vec<(Range<usize>, String)> = vec![];
+        for (range, rule_index, text) in matches {
+            if kept
+                .iter()
+                .any(|(k, _)| range.start < k.end && k.start < range.end)
+            {
+                // Two different markers matched overlapping spans; keep whichever was found by
+                // the earlier-registered rule (footnotes always win) and drop the other.
+                warn!(
+                    "Marker '{}' match at byte {} overlaps an earlier marker's match; ignoring it",
+                    self.markers[rule_index].name, range.start
+                );
+                continue;
+            }
+            kept.push((range, text));
+        }
+        kept.sort_by_key(|(range, _)| range.start);
+
+        let mut result = String::with_capacity(content.len());
+        let mut last_end = 0;
+        for (range, text) in kept {
+            result.push_str(&content[last_end..range.start]);
+            result.push_str(&text);
+            last_end = range.end;
+        }
+        result.push_str(&content[last_end..]);
+
+        if !footnotes.is_empty() {
+            result.push_str(&self.footnote_trailer(footnotes, &occurrences));
+        }
+
+        chap.content = result;
+        Ok(())
+    }
 }
 
 impl Preprocessor for Footnote {
@@ -114,48 +489,21 @@ impl Preprocessor for Footnote {
         NAME
     }
 
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
+        if !self.supports_renderer(&ctx.renderer) {
+            return Ok(book);
+        }
+
+        let mut result = Ok(());
         book.for_each_mut(|item| {
+            if result.is_err() {
+                return;
+            }
             if let mdbook::book::BookItem::Chapter(chap) = item {
-                let mut footnotes = vec![];
-                chap.content = FOOTNOTE_RE
-                    .replace_all(&chap.content, |caps: &regex::Captures| {
-                        let content = caps.name("content").unwrap().as_str().to_owned();
-                        footnotes.push(content);
-                        let idx = footnotes.len();
-                        if self.md_footnotes {
-                            format!("[^{}]", idx)
-                        } else {
-                            format!(
-                                "<sup><a name=\"to-footnote-{}\">[{}](#footnote-{})</a></sup>",
-                                idx, idx, idx
-                            )
-                        }
-                    })
-                    .to_string();
-
-                if !footnotes.is_empty() {
-                    if self.md_footnotes {
-                        chap.content += "<p><hr/>\n";
-                    } else {
-                        chap.content += "\n---\n";
-                    }
-                    for (idx, content) in footnotes.into_iter().enumerate() {
-                        if self.md_footnotes {
-                            chap.content += &format!("\n\n[^{}]: {}", idx + 1, content);
-                        } else {
-                            chap.content += &format!(
-                                "\n\n<a name=\"footnote-{}\">[{}](#to-footnote-{})</a>: {}",
-                                idx + 1,
-                                idx + 1,
-                                idx + 1,
-                                content
-                            );
-                        }
-                    }
-                }
+                result = self.expand_markers(chap);
             }
         });
+        result?;
         Ok(book)
     }
 